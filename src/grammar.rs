@@ -0,0 +1,304 @@
+//! Support for fetching and building tree-sitter grammars that are declared in the user's
+//! configuration, rather than being statically linked into this binary.
+//!
+//! This mirrors the grammar-fetching design used by `helix-loader`: a [`GrammarConfiguration`]
+//! names a grammar and where its sources come from, and [`GrammarRegistry`] takes care of
+//! fetching, building, and caching the resulting shared library so that it can be handed off to
+//! [`crate::parse::load_grammar_dylib`].
+
+use anyhow::{format_err, Context, Result};
+use log::{debug, info};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+/// Where a configured grammar's sources should be fetched from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum GrammarSource {
+    /// Sources that already live on disk, e.g. for local development of a grammar
+    Local { path: PathBuf },
+    /// Sources fetched from a git remote, pinned to a specific revision
+    Git {
+        remote: String,
+        rev: String,
+        /// Subdirectory within the repository that contains the grammar, for repos that bundle
+        /// more than one grammar (e.g. `tree-sitter-typescript`)
+        #[serde(default)]
+        subpath: Option<String>,
+    },
+}
+
+impl GrammarSource {
+    /// A filesystem-safe key identifying this source, used to namespace the on-disk cache so
+    /// that two different revisions of the same grammar don't collide
+    fn cache_key(&self) -> String {
+        match self {
+            GrammarSource::Local { .. } => "local".to_string(),
+            GrammarSource::Git { rev, .. } => rev.replace(['/', '\\'], "_"),
+        }
+    }
+}
+
+/// A single grammar declared in the user's configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct GrammarConfiguration {
+    pub grammar_id: String,
+    #[serde(flatten)]
+    pub source: GrammarSource,
+}
+
+/// Fetches, builds, and caches grammars that were declared in the user's configuration
+///
+/// Built grammars are cached under `cache_dir` by `(grammar_id, rev)` so that a grammar pinned to
+/// an unchanged revision is only ever compiled once.
+pub struct GrammarRegistry {
+    configs: HashMap<String, GrammarConfiguration>,
+    /// Mapping of file extension to `grammar_id`, as declared alongside the grammar configs
+    extensions: HashMap<String, String>,
+    cache_dir: PathBuf,
+}
+
+impl GrammarRegistry {
+    pub fn new(
+        configs: Vec<GrammarConfiguration>,
+        extensions: HashMap<String, String>,
+        cache_dir: PathBuf,
+    ) -> Self {
+        let configs = configs
+            .into_iter()
+            .map(|c| (c.grammar_id.clone(), c))
+            .collect();
+        Self {
+            configs,
+            extensions,
+            cache_dir,
+        }
+    }
+
+    /// Look up the `grammar_id` configured for a file extension, if any
+    pub fn grammar_id_for_ext(&self, ext: &str) -> Option<&str> {
+        self.extensions.get(ext).map(String::as_str)
+    }
+
+    /// Whether a grammar with this id is declared in the configuration, regardless of whether
+    /// it's been fetched/built/loaded yet
+    pub fn has_grammar(&self, grammar_id: &str) -> bool {
+        self.configs.contains_key(grammar_id)
+    }
+
+    /// Make sure `grammar_id` is fetched and built, returning the directory its compiled shared
+    /// library lives in
+    ///
+    /// This fetches the grammar's sources (cloning or updating a git checkout as needed) and
+    /// compiles them with the `cc` crate, skipping the build entirely if a cached artifact is
+    /// already newer than the sources it would be built from.
+    pub fn ensure_built(&self, grammar_id: &str) -> Result<PathBuf> {
+        let config = self
+            .configs
+            .get(grammar_id)
+            .ok_or_else(|| format_err!("No grammar configured with id \"{}\"", grammar_id))?;
+
+        let src_dir = self.fetch_source(config)?;
+        // Cache by (grammar_id, rev): each revision gets its own lib directory so that pinning
+        // two different revisions of the same grammar can't clobber one another's artifact.
+        let lib_dir = self
+            .cache_dir
+            .join("lib")
+            .join(grammar_id)
+            .join(config.source.cache_key());
+        fs::create_dir_all(&lib_dir)
+            .with_context(|| format!("Failed to create grammar cache dir {}", lib_dir.display()))?;
+        let lib_path = lib_dir.join(format!(
+            "tree-sitter-{}{}",
+            grammar_id,
+            std::env::consts::DLL_SUFFIX
+        ));
+
+        if is_up_to_date(&lib_path, &src_dir)? {
+            debug!(
+                "Cached grammar for \"{}\" is up to date, skipping rebuild",
+                grammar_id
+            );
+            return Ok(lib_dir);
+        }
+
+        build_grammar(grammar_id, &src_dir, &lib_path)?;
+        Ok(lib_dir)
+    }
+
+    /// Resolve the grammar's sources to a local directory, fetching them first if necessary
+    fn fetch_source(&self, config: &GrammarConfiguration) -> Result<PathBuf> {
+        match &config.source {
+            GrammarSource::Local { path } => Ok(path.clone()),
+            GrammarSource::Git {
+                remote,
+                rev,
+                subpath,
+            } => {
+                // Cache by (grammar_id, rev): a stale revision's checkout never gets reused or
+                // fetched over by a newer one pinned to a different rev.
+                let checkout_dir = self
+                    .cache_dir
+                    .join("sources")
+                    .join(&config.grammar_id)
+                    .join(config.source.cache_key());
+                fetch_git_rev(remote, rev, &checkout_dir)?;
+                Ok(match subpath {
+                    Some(sub) => checkout_dir.join(sub),
+                    None => checkout_dir,
+                })
+            }
+        }
+    }
+}
+
+/// Shallow-clone (or update) `remote` into `dir` and check it out at `rev`
+///
+/// `dir` is expected to be namespaced by `rev` already (see [`GrammarSource::cache_key`]), so a
+/// checkout that's already present there was necessarily fetched at `rev`; we only need a marker
+/// to tell a complete checkout apart from one that was interrupted partway through, and to avoid
+/// re-running `git fetch`/`checkout` on every invocation once it's done.
+fn fetch_git_rev(remote: &str, rev: &str, dir: &Path) -> Result<()> {
+    let marker = dir.join(".diffsitter-rev");
+    if fs::read_to_string(&marker).map_or(false, |cached| cached.trim() == rev) {
+        debug!(
+            "Grammar checkout {} is already at rev {}, skipping fetch",
+            dir.display(),
+            rev
+        );
+        return Ok(());
+    }
+
+    if !dir.join(".git").exists() {
+        fs::create_dir_all(dir.parent().unwrap_or(dir))?;
+        info!("Cloning grammar source {} into {}", remote, dir.display());
+        run_git(
+            &[
+                "clone",
+                "--depth",
+                "1",
+                "--no-checkout",
+                remote,
+                dir.to_str().unwrap(),
+            ],
+            None,
+        )?;
+    }
+
+    info!("Fetching grammar revision {} from {}", rev, remote);
+    run_git(&["fetch", "--depth", "1", "origin", rev], Some(dir))?;
+    run_git(&["checkout", "FETCH_HEAD"], Some(dir))?;
+    fs::write(&marker, rev)
+        .with_context(|| format!("Failed to write rev marker in {}", dir.display()))?;
+    Ok(())
+}
+
+fn run_git(args: &[&str], cwd: Option<&Path>) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.args(args);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    let status = cmd
+        .status()
+        .with_context(|| format!("Failed to run git {:?}", args))?;
+    if !status.success() {
+        return Err(format_err!("git {:?} exited with {}", args, status));
+    }
+    Ok(())
+}
+
+/// Whether the compiled grammar at `lib_path` is newer than every source file it would be built
+/// from, i.e. whether we can skip rebuilding it
+fn is_up_to_date(lib_path: &Path, src_dir: &Path) -> Result<bool> {
+    let lib_mtime = match fs::metadata(lib_path).and_then(|m| m.modified()) {
+        Ok(t) => t,
+        Err(_) => return Ok(false),
+    };
+
+    for src in grammar_sources(src_dir)? {
+        let src_mtime: SystemTime = fs::metadata(&src)?.modified()?;
+        if src_mtime > lib_mtime {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Locate the C/C++ sources that make up a grammar: `src/parser.c`, plus an optional scanner
+fn grammar_sources(src_dir: &Path) -> Result<Vec<PathBuf>> {
+    let parser = src_dir.join("src").join("parser.c");
+    if !parser.exists() {
+        return Err(format_err!(
+            "Grammar source directory {} has no src/parser.c",
+            src_dir.display()
+        ));
+    }
+    let mut sources = vec![parser];
+    for scanner in ["scanner.c", "scanner.cc"] {
+        let path = src_dir.join("src").join(scanner);
+        if path.exists() {
+            sources.push(path);
+        }
+    }
+    Ok(sources)
+}
+
+/// Compile a grammar's sources into a shared library at `lib_path`
+///
+/// `cc::Build::get_compiler()` only resolves which compiler to invoke (and does so based on
+/// `.cpp()`); it does NOT apply flags set via `.include()`/`.pic()`/`.opt_level()`/etc. to the
+/// `Command` it returns, since those are only threaded through `compile()`'s own per-file
+/// invocations. Since we need a single `-shared` invocation instead, every flag the build needs
+/// (most importantly `-fPIC`, without which linking tree-sitter's static parse tables into a
+/// `.so` fails) has to be passed to the `Command` explicitly.
+///
+/// This only knows how to drive GCC/Clang-style compilers. `cc` resolves to MSVC's `cl.exe` on
+/// the default Windows toolchain, which doesn't understand any of those flags (and needs a very
+/// different invocation, e.g. `/LD`/`/Fe:`), so that case is rejected with a clear error rather
+/// than handed a command line it will reject anyway.
+fn build_grammar(grammar_id: &str, src_dir: &Path, lib_path: &Path) -> Result<()> {
+    info!("Building grammar \"{}\" from {}", grammar_id, src_dir.display());
+    let sources = grammar_sources(src_dir)?;
+    let is_cpp = sources
+        .iter()
+        .any(|p| p.extension().map_or(false, |e| e == "cc"));
+
+    let mut build = cc::Build::new();
+    build.cpp(is_cpp);
+    let compiler = build.get_compiler();
+
+    if compiler.is_like_msvc() {
+        return Err(format_err!(
+            "Building grammar \"{}\" on demand isn't supported with the MSVC toolchain ({}); \
+             configure a prebuilt grammar shared library instead",
+            grammar_id,
+            compiler.path().display()
+        ));
+    }
+
+    let mut cmd = compiler.to_command();
+    cmd.arg("-fPIC");
+    cmd.arg("-shared");
+    cmd.arg("-O2");
+    cmd.arg("-w");
+    cmd.arg(format!("-I{}", src_dir.join("src").display()));
+    cmd.args(sources.iter().map(|p| p.as_os_str()));
+    cmd.arg("-o").arg(lib_path);
+
+    let status = cmd
+        .status()
+        .with_context(|| format!("Failed to invoke compiler for grammar \"{}\"", grammar_id))?;
+    if !status.success() {
+        return Err(format_err!(
+            "Failed to build grammar \"{}\": compiler exited with {}",
+            grammar_id,
+            status
+        ));
+    }
+    Ok(())
+}
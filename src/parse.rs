@@ -2,13 +2,108 @@
 
 include!(concat!(env!("OUT_DIR"), "/generated_grammar.rs"));
 
+use crate::grammar::GrammarRegistry;
 use anyhow::{format_err, Result};
 use log::{debug, info};
 use logging_timer::time;
+use once_cell::sync::Lazy;
 use std::collections::HashMap;
-use std::{fs, path::Path};
+use std::sync::Mutex;
+use std::{fs, path::Path, path::PathBuf};
 use tree_sitter::{Parser, Tree};
 
+/// The file name (sans platform-specific extension) tree-sitter grammars built by diffsitter
+/// are expected to use: `tree-sitter-<lang>.{so,dylib,dll}`
+const GRAMMAR_LIB_PREFIX: &str = "tree-sitter-";
+
+/// A grammar that was `dlopen`ed at runtime, along with the handle keeping it mapped
+struct LoadedGrammar {
+    /// Never read directly; its only job is to outlive `language`, which borrows from the
+    /// library's mapped memory
+    _library: libloading::Library,
+    language: Language,
+}
+
+/// Registry of grammars that were loaded dynamically at runtime, keyed by language name
+///
+/// Each `Library` has to be kept alive for as long as the process might call into the `Language`
+/// it produced, since the `Language` only holds raw function pointers into the mapped shared
+/// object. We never unload these, so we just stash them here for the lifetime of the process
+/// rather than leaking each one individually. This also means a grammar only has to be `dlopen`ed
+/// once per process, even if it's requested again later.
+static DYLIB_REGISTRY: Lazy<Mutex<HashMap<String, LoadedGrammar>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The path a dynamically loaded grammar for `lang` is expected to live at within `dir`
+fn grammar_dylib_path(lang: &str, dir: &Path) -> PathBuf {
+    let file_name = format!("{}{}{}", GRAMMAR_LIB_PREFIX, lang, std::env::consts::DLL_SUFFIX);
+    dir.join(file_name)
+}
+
+/// Load a tree-sitter grammar for `lang` from a shared library in `dir`
+///
+/// This looks for a file named `tree-sitter-<lang>` with the platform's native shared library
+/// extension (e.g. `tree-sitter-python.so` on Linux), `dlopen`s it, and resolves the
+/// `tree_sitter_<lang>` symbol that every tree-sitter grammar exports. If `lang` was already
+/// loaded this way, the cached [`Language`] is returned without touching the filesystem again.
+///
+/// The returned [`Language`] is validated against tree-sitter's supported ABI range so that a
+/// grammar built against an incompatible tree-sitter version is rejected with an error instead of
+/// causing undefined behavior later on.
+fn load_grammar_dylib(lang: &str, dir: &Path) -> Result<Language> {
+    let mut registry = DYLIB_REGISTRY.lock().expect("dylib registry mutex poisoned");
+    if let Some(loaded) = registry.get(lang) {
+        return Ok(loaded.language.clone());
+    }
+
+    let path = grammar_dylib_path(lang, dir);
+
+    // SAFETY: we're loading a file that is expected to be a tree-sitter grammar exposing a
+    // `tree_sitter_<lang>` symbol with the signature below. There's no way to fully guarantee
+    // that at the type level; we rely on the `Language::version()` check just after to catch
+    // the common failure mode of an incompatible/garbage grammar.
+    let library = unsafe {
+        libloading::Library::new(&path)
+            .map_err(|e| format_err!("Failed to load grammar library {}: {}", path.display(), e))?
+    };
+
+    let symbol_name = format!("tree_sitter_{}", lang);
+    // SAFETY: see above; the symbol is looked up by the name tree-sitter's own code generator
+    // uses for every grammar, and we check the returned language's ABI version before using it.
+    let language = unsafe {
+        let constructor: libloading::Symbol<unsafe extern "C" fn() -> Language> = library
+            .get(symbol_name.as_bytes())
+            .map_err(|e| format_err!("Grammar {} is missing symbol {}: {}", lang, symbol_name, e))?;
+        constructor()
+    };
+
+    let version = language.version();
+    if !(tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION..=tree_sitter::LANGUAGE_VERSION)
+        .contains(&version)
+    {
+        return Err(format_err!(
+            "Grammar {} was built for tree-sitter language ABI {}, which is outside the \
+             supported range {}..={}",
+            lang,
+            version,
+            tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION,
+            tree_sitter::LANGUAGE_VERSION
+        ));
+    }
+
+    // Keep the library mapped for the rest of the process's life; `language` borrows from it.
+    let result = language.clone();
+    registry.insert(
+        lang.to_string(),
+        LoadedGrammar {
+            _library: library,
+            language,
+        },
+    );
+
+    Ok(result)
+}
+
 /// A mapping of file extensions to their associated languages
 ///
 /// The languages correspond to grammars from `tree-sitter`
@@ -36,70 +131,226 @@ static FILE_EXTS: phf::Map<&'static str, &'static str> = phf_map! {
     "hcl" => "hcl",
 };
 
+/// A mapping of well-known bare file names to their associated languages
+///
+/// This covers files that are conventionally extensionless, e.g. `Makefile`, as well as names
+/// like `CMakeLists.txt` whose extension alone doesn't identify the language
+static FILE_NAMES: phf::Map<&'static str, &'static str> = phf_map! {
+    "Makefile" => "make",
+    "GNUmakefile" => "make",
+    "Dockerfile" => "dockerfile",
+    "CMakeLists.txt" => "cmake",
+    ".bashrc" => "bash",
+    ".bash_profile" => "bash",
+};
+
+/// A mapping of shebang interpreter names to their associated languages
+///
+/// Interpreter names are matched after stripping a trailing version number, e.g. `python3` and
+/// `ruby2.7` both match as `python`/`ruby` respectively
+static SHEBANG_INTERPRETERS: phf::Map<&'static str, &'static str> = phf_map! {
+    "python" => "python",
+    "sh" => "bash",
+    "bash" => "bash",
+    "ruby" => "ruby",
+};
+
+/// Extract the interpreter name from a shebang line, e.g. `#!/usr/bin/env python3` or
+/// `#!/bin/bash`
+///
+/// This resolves the `/usr/bin/env <interpreter>` indirection and strips a trailing version
+/// number (`python3` -> `python`, `ruby2.7` -> `ruby`) so the result can be looked up in
+/// [`SHEBANG_INTERPRETERS`].
+fn interpreter_from_shebang(first_line: &str) -> Option<String> {
+    let rest = first_line.strip_prefix("#!")?.trim();
+    let mut args = rest.split_whitespace();
+    let first_arg = args.next()?;
+    let first_name = Path::new(first_arg).file_name()?.to_str()?;
+    let interpreter = if first_name == "env" {
+        args.next()?
+    } else {
+        first_name
+    };
+    let stripped = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    Some(stripped.to_string())
+}
+
+/// Check a path's whole file name against the user's `overrides` and [`FILE_NAMES`]
+///
+/// This is consulted before the extension-based lookup, so that dotted special names like
+/// `CMakeLists.txt` are matched on their full name rather than being handed to
+/// [`language_from_ext`] as extension `txt`. Returns `None` (rather than an error) when neither
+/// table has an entry for `file_name`, so the caller can fall through to extension/shebang-based
+/// detection.
+fn language_from_special_filename(
+    file_name: &str,
+    overrides: Option<&HashMap<String, String>>,
+    grammar_dir: Option<&Path>,
+    registry: Option<&GrammarRegistry>,
+) -> Option<Result<Language>> {
+    if let Some(Some(language_str)) = overrides.map(|x| x.get(file_name)) {
+        info!(
+            "Deduced language \"{}\" from file name \"{}\" provided from user mappings",
+            language_str, file_name
+        );
+        return Some(generate_language(language_str, grammar_dir, registry));
+    }
+
+    if let Some(&language_str) = FILE_NAMES.get(file_name) {
+        info!(
+            "Deduced language \"{}\" from file name \"{}\"",
+            language_str, file_name
+        );
+        return Some(generate_language(language_str, grammar_dir, registry));
+    }
+
+    None
+}
+
+/// Infer a language from a file's `#!` shebang line, as a last resort for files that have neither
+/// a recognized extension nor a well-known name
+fn language_from_shebang(
+    p: &Path,
+    text: &str,
+    grammar_dir: Option<&Path>,
+    registry: Option<&GrammarRegistry>,
+) -> Result<Language> {
+    if let Some(interpreter) = text.lines().next().and_then(interpreter_from_shebang) {
+        if let Some(&language_str) = SHEBANG_INTERPRETERS.get(interpreter.as_str()) {
+            info!(
+                "Deduced language \"{}\" from shebang interpreter \"{}\"",
+                language_str, interpreter
+            );
+            return generate_language(language_str, grammar_dir, registry);
+        }
+    }
+
+    Err(format_err!(
+        "Could not deduce a language for file name \"{}\"",
+        p.to_string_lossy()
+    ))
+}
+
 /// Generate a [tree sitter language](Language) from a language string
 ///
-/// This will return an error if an unknown string is provided
-fn generate_language(lang: &str) -> Result<Language> {
+/// This will check the statically linked grammars first. If `lang` isn't one of those and
+/// `grammar_dir` is supplied, it will fall back to [`load_grammar_dylib`] and try to `dlopen` a
+/// grammar for `lang` from that directory. Failing that, if `registry` is supplied and has a
+/// grammar configured for `lang`, it will be fetched/built on demand and loaded from there. This
+/// will return an error if the language can't be found through any of those paths.
+///
+/// If a grammar file actually exists in `grammar_dir` but fails to load (e.g. its ABI is
+/// incompatible, or it's missing the expected symbol), that error is surfaced directly instead of
+/// being swallowed and reported as a generic "unsupported language" - the whole point of the ABI
+/// check in `load_grammar_dylib` is to give the user something more actionable than a segfault.
+fn generate_language(
+    lang: &str,
+    grammar_dir: Option<&Path>,
+    registry: Option<&GrammarRegistry>,
+) -> Result<Language> {
     info!("Using tree-sitter parser for language {}", lang);
-    match LANGUAGES.get(lang) {
-        Some(grammar_fn) => Ok(unsafe { grammar_fn() }),
-        None => Err(format_err!("Unsupported language {}", lang)),
+    if let Some(grammar_fn) = LANGUAGES.get(lang) {
+        return Ok(unsafe { grammar_fn() });
     }
+    if let Some(dir) = grammar_dir {
+        if grammar_dylib_path(lang, dir).exists() {
+            return load_grammar_dylib(lang, dir);
+        }
+    }
+    if let Some(registry) = registry {
+        let lib_dir = registry.ensure_built(lang)?;
+        return load_grammar_dylib(lang, &lib_dir);
+    }
+    Err(format_err!("Unsupported language {}", lang))
 }
 
 /// Create an instance of a language from a file extension
 ///
-/// The user may optionally provide a hashmap with overrides
+/// The user may optionally provide a hashmap with overrides. If `grammar_dir` is supplied,
+/// languages that aren't statically linked into this binary will be looked up as shared
+/// libraries in that directory. If `registry` is supplied, an extension that isn't covered by
+/// `overrides` or the built-in mappings will also be checked against the configured grammars.
 pub fn language_from_ext(
     ext: &str,
     overrides: Option<&HashMap<String, String>>,
+    grammar_dir: Option<&Path>,
+    registry: Option<&GrammarRegistry>,
 ) -> Result<Language> {
     if let Some(Some(language_str)) = overrides.map(|x| x.get(ext)) {
         info!(
             "Deduced language \"{}\" from extension \"{}\" provided from user mappings",
             language_str, ext
         );
-        return generate_language(language_str);
+        return generate_language(language_str, grammar_dir, registry);
     };
-    let language_str = match FILE_EXTS.get(ext) {
-        Some(&language_str) => {
-            info!(
-                "Deduced language \"{}\" from extension \"{}\" from default mappings",
-                language_str, ext
-            );
-            Ok(language_str)
-        }
-        None => Err(format_err!("Unsupported filetype \"{}\"", ext)),
-    }?;
-    generate_language(language_str)
+    if let Some(&language_str) = FILE_EXTS.get(ext) {
+        info!(
+            "Deduced language \"{}\" from extension \"{}\" from default mappings",
+            language_str, ext
+        );
+        return generate_language(language_str, grammar_dir, registry);
+    }
+    if let Some(language_str) = registry.and_then(|r| r.grammar_id_for_ext(ext)) {
+        info!(
+            "Deduced language \"{}\" from extension \"{}\" from configured grammars",
+            language_str, ext
+        );
+        return generate_language(language_str, grammar_dir, registry);
+    }
+    Err(format_err!("Unsupported filetype \"{}\"", ext))
 }
 
 /// Parse a file to an AST
 ///
 /// The user may optionally supply the language to use. If the language is not supplied, it will be
-/// inferrred from the file's extension.
+/// inferred by checking, in order: the user's `overrides` and [`FILE_NAMES`] against the whole
+/// file name (so dotted special names like `CMakeLists.txt` are recognized before extension-based
+/// detection ever sees `txt`), then the file's extension, then its `#!` shebang line - the
+/// shebang is consulted both for extensionless files and for files whose extension isn't
+/// recognized (e.g. a script named `deploy.inc`). If `grammar_dir` is supplied, languages that
+/// aren't statically linked into this binary will be looked up as shared libraries in that
+/// directory. If `registry` is supplied, a configured grammar that isn't already on disk will be
+/// fetched and built automatically.
 #[time("info", "parse::{}")]
 pub fn parse_file(
     p: &Path,
     language: Option<&str>,
     overrides: Option<&HashMap<String, String>>,
+    grammar_dir: Option<&Path>,
+    registry: Option<&GrammarRegistry>,
 ) -> Result<Tree> {
     let text = fs::read_to_string(p)?;
     let mut parser = Parser::new();
     let language = match language {
         Some(x) => {
             info!("Using language {} with parser", x);
-            generate_language(x)
+            generate_language(x, grammar_dir, registry)
         }
         None => {
-            if let Some(ext) = p.extension() {
-                let ext_str = ext.to_string_lossy();
-                language_from_ext(&ext_str, overrides)
-            } else {
-                Err(format_err!(
-                    "Could not deduce an extension for file name \"{}\"",
-                    p.to_string_lossy()
-                ))
+            let file_name = p.file_name().map(|f| f.to_string_lossy());
+            let by_name = file_name.as_deref().and_then(|name| {
+                language_from_special_filename(name, overrides, grammar_dir, registry)
+            });
+            match by_name {
+                Some(result) => result,
+                None => match p.extension() {
+                    // An unrecognized extension still falls through to the shebang line rather
+                    // than erroring immediately, e.g. a script named `deploy.inc` starting with
+                    // `#!/usr/bin/env python3`. The extension-based error is kept around and
+                    // only surfaced if the shebang doesn't resolve to a language either, since
+                    // it's more specific than the generic "no shebang" error.
+                    Some(ext) => {
+                        let ext_str = ext.to_string_lossy();
+                        match language_from_ext(&ext_str, overrides, grammar_dir, registry) {
+                            Ok(language) => Ok(language),
+                            Err(ext_err) => {
+                                language_from_shebang(p, &text, grammar_dir, registry)
+                                    .or(Err(ext_err))
+                            }
+                        }
+                    }
+                    None => language_from_shebang(p, &text, grammar_dir, registry),
+                },
             }
         }
     }?;
@@ -124,6 +375,89 @@ pub fn supported_languages() -> Vec<&'static str> {
     keys
 }
 
+/// Return the `(extension, language)` pairs this instance of the tool knows about out of the box,
+/// in alphabetically sorted order by extension
+pub fn supported_extensions() -> Vec<(&'static str, &'static str)> {
+    let mut exts: Vec<(&'static str, &'static str)> =
+        FILE_EXTS.entries().map(|(&k, &v)| (k, v)).collect();
+    exts.sort_unstable();
+    exts
+}
+
+/// Where a language's grammar is made available from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LanguageSource {
+    /// Compiled directly into this binary
+    Static,
+    /// `dlopen`ed from a shared library at runtime, or configured in a [`GrammarRegistry`] to be
+    /// built and loaded that way on first use
+    Dynamic,
+}
+
+/// Diagnostic information about a single supported language
+///
+/// This is meant to power a `--list-languages` style health table, so callers can see at a
+/// glance which file extensions map to which languages, whether a grammar came from this binary
+/// or was loaded dynamically, and which tree-sitter ABI version it was built against.
+#[derive(Debug, Clone)]
+pub struct LanguageInfo {
+    pub name: String,
+    pub extensions: Vec<&'static str>,
+    pub source: LanguageSource,
+    /// The ABI version of the grammar that's currently loaded, or `None` if `source` is
+    /// [`LanguageSource::Dynamic`] but the grammar is only configured so far and hasn't actually
+    /// been fetched/built/loaded yet
+    pub abi_version: Option<usize>,
+}
+
+/// Look up diagnostic info for `lang`
+///
+/// This covers grammars statically linked into this binary, grammars that have already been
+/// loaded dynamically via [`load_grammar_dylib`], and - if `registry` is supplied - grammars that
+/// are merely configured there but haven't been built yet (reported with `abi_version: None`,
+/// since that isn't known until the grammar is actually loaded). Returns `None` if `lang` isn't
+/// covered by any of the three.
+pub fn language_info(lang: &str, registry: Option<&GrammarRegistry>) -> Option<LanguageInfo> {
+    let extensions = FILE_EXTS
+        .entries()
+        .filter(|(_, &v)| v == lang)
+        .map(|(&k, _)| k)
+        .collect();
+
+    if let Some(grammar_fn) = LANGUAGES.get(lang) {
+        let language = unsafe { grammar_fn() };
+        return Some(LanguageInfo {
+            name: lang.to_string(),
+            extensions,
+            source: LanguageSource::Static,
+            abi_version: Some(language.version()),
+        });
+    }
+
+    {
+        let dylib_registry = DYLIB_REGISTRY.lock().expect("dylib registry mutex poisoned");
+        if let Some(loaded) = dylib_registry.get(lang) {
+            return Some(LanguageInfo {
+                name: lang.to_string(),
+                extensions,
+                source: LanguageSource::Dynamic,
+                abi_version: Some(loaded.language.version()),
+            });
+        }
+    }
+
+    if registry.map_or(false, |r| r.has_grammar(lang)) {
+        return Some(LanguageInfo {
+            name: lang.to_string(),
+            extensions,
+            source: LanguageSource::Dynamic,
+            abi_version: None,
+        });
+    }
+
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -147,4 +481,54 @@ mod tests {
 
         assert!(failures.is_empty(), "{:#?}", failures);
     }
+
+    #[test]
+    fn test_interpreter_from_shebang_env_indirection() {
+        assert_eq!(
+            interpreter_from_shebang("#!/usr/bin/env python3"),
+            Some("python".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpreter_from_shebang_direct_path() {
+        assert_eq!(
+            interpreter_from_shebang("#!/bin/bash"),
+            Some("bash".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpreter_from_shebang_strips_trailing_version() {
+        assert_eq!(
+            interpreter_from_shebang("#!/usr/bin/env ruby2.7"),
+            Some("ruby".to_string())
+        );
+    }
+
+    #[test]
+    fn test_interpreter_from_shebang_non_shebang_line() {
+        assert_eq!(interpreter_from_shebang("fn main() {}"), None);
+    }
+
+    #[test]
+    fn test_supported_extensions_contains_known_mapping() {
+        let exts = supported_extensions();
+        assert!(exts.contains(&("rs", "rust")));
+        assert!(exts.contains(&("py", "python")));
+    }
+
+    #[test]
+    fn test_language_info_static_grammar() {
+        let info = language_info("rust", None).expect("rust is statically linked");
+        assert_eq!(info.name, "rust");
+        assert_eq!(info.source, LanguageSource::Static);
+        assert!(info.extensions.contains(&"rs"));
+        assert!(info.abi_version.is_some());
+    }
+
+    #[test]
+    fn test_language_info_unknown_language() {
+        assert!(language_info("not-a-real-language", None).is_none());
+    }
 }